@@ -1,6 +1,9 @@
 use std::{
+    collections::HashMap,
     fs::{File, OpenOptions},
-    io::{BufRead, Read},
+    future::Future,
+    io::{BufRead, Read, Write},
+    pin::Pin,
 };
 
 use futures_util::StreamExt;
@@ -17,21 +20,324 @@ use rig::{
     streaming::StreamedAssistantContent,
 };
 
+const MAX_AGENT_STEPS: usize = 8;
+
+type ToolHandlerOutput = Result<String, String>;
+type ToolHandlerFuture = Pin<Box<dyn Future<Output = ToolHandlerOutput> + Send>>;
+
+struct ToolRegistry {
+    handlers: HashMap<String, Box<dyn Fn(serde_json::Value) -> ToolHandlerFuture + Send + Sync>>,
+}
+
+impl ToolRegistry {
+    fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    fn register<F, Fut>(&mut self, name: &str, handler: F)
+    where
+        F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ToolHandlerOutput> + Send + 'static,
+    {
+        self.handlers
+            .insert(name.to_string(), Box::new(move |args| Box::pin(handler(args))));
+    }
+
+    /// Returns `None` when no handler is registered for `name`, so the
+    /// caller can fall back to the file-based resume path.
+    async fn dispatch(&self, name: &str, args: serde_json::Value) -> Option<ToolHandlerOutput> {
+        match self.handlers.get(name) {
+            Some(handler) => Some(handler(args).await),
+            None => None,
+        }
+    }
+}
+
+fn parse_and_validate_tool_args(
+    call: &rig::message::ToolCall,
+    tools: &[ToolDefinition],
+) -> Result<serde_json::Value, String> {
+    let args = call.function.arguments.clone();
+
+    if let Some(definition) = tools.iter().find(|tool| tool.name == call.function.name) {
+        validate_against_schema(&args, &definition.parameters).map_err(|err| {
+            format!(
+                "Arguments for tool `{}` did not match its schema: {err}. Please retry with corrected JSON arguments.",
+                call.function.name
+            )
+        })?;
+    }
+
+    Ok(args)
+}
+
+/// Intentionally shallow: only checks `required` and top-level `properties`
+/// types, no nested schemas, `$ref`, or `oneOf`.
+fn validate_against_schema(value: &serde_json::Value, schema: &serde_json::Value) -> Result<(), String> {
+    let Some(fields) = value.as_object() else {
+        return Err("expected a JSON object".to_string());
+    };
+
+    if let Some(required) = schema.get("required").and_then(|r| r.as_array()) {
+        for key in required {
+            if let Some(key) = key.as_str()
+                && !fields.contains_key(key)
+            {
+                return Err(format!("missing required field `{key}`"));
+            }
+        }
+    }
+
+    if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+        for (key, field_value) in fields {
+            let Some(expected_type) = properties
+                .get(key)
+                .and_then(|property| property.get("type"))
+                .and_then(|t| t.as_str())
+            else {
+                continue;
+            };
+
+            if !json_value_matches_type(field_value, expected_type) {
+                return Err(format!(
+                    "field `{key}` should be of type `{expected_type}`, got `{field_value}`"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn json_value_matches_type(value: &serde_json::Value, expected_type: &str) -> bool {
+    match expected_type {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+/// Tools whose results must never be served from the cache.
+const NO_CACHE_TOOLS: &[&str] = &["get_weather"];
+
+fn canonicalize_json(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let sorted: std::collections::BTreeMap<String, serde_json::Value> = map
+                .iter()
+                .map(|(key, value)| (key.clone(), canonicalize_json(value)))
+                .collect();
+            serde_json::Value::Object(sorted.into_iter().collect())
+        }
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(canonicalize_json).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+fn tool_cache_key(tool_name: &str, args: &serde_json::Value) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let canonical_args = serde_json::to_string(&canonicalize_json(args)).unwrap();
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    tool_name.hash(&mut hasher);
+    canonical_args.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ToolCacheEntry {
+    key: String,
+    tool_name: String,
+    result: String,
+}
+
+struct ToolCache<W: std::io::Write> {
+    writer: W,
+    entries: HashMap<String, String>,
+}
+
+impl<W: std::io::Write> ToolCache<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn new_with_entries(writer: W, entries: HashMap<String, String>) -> Self {
+        Self { writer, entries }
+    }
+
+    fn get(&self, key: &str) -> Option<&String> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: String, tool_name: String, result: String) {
+        let entry = ToolCacheEntry {
+            key: key.clone(),
+            tool_name,
+            result: result.clone(),
+        };
+
+        self.writer
+            .write_all(format!("{}\n", serde_json::to_string(&entry).unwrap()).as_bytes())
+            .unwrap();
+        self.writer.flush().unwrap();
+
+        self.entries.insert(key, result);
+    }
+}
+
+/// Tools named with this prefix require operator approval before running.
+const CONFIRMATION_PREFIX: &str = "may_";
+
+fn requires_confirmation(tool_name: &str) -> bool {
+    tool_name.starts_with(CONFIRMATION_PREFIX)
+}
+
+enum Confirmation {
+    Approved,
+    Rejected,
+}
+
+fn confirm_tool_call(
+    call: &rig::message::ToolCall,
+    args: &mut serde_json::Value,
+    lines: &mut std::io::Lines<std::io::StdinLock<'static>>,
+) -> Confirmation {
+    println!(
+        "\n[Approval required] `{}` wants to run with arguments:\n{}",
+        call.function.name,
+        serde_json::to_string_pretty(args).unwrap()
+    );
+
+    loop {
+        print!("Approve (a) / edit (e) / reject (r)? ");
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+
+        let Some(Ok(line)) = lines.next() else {
+            return Confirmation::Rejected;
+        };
+
+        match line.trim() {
+            "" | "a" | "approve" => return Confirmation::Approved,
+            "r" | "reject" => return Confirmation::Rejected,
+            "e" | "edit" => {
+                print!("Enter replacement JSON arguments: ");
+                std::io::Write::flush(&mut std::io::stdout()).unwrap();
+
+                let Some(Ok(edited)) = lines.next() else {
+                    return Confirmation::Rejected;
+                };
+
+                match serde_json::from_str(&edited) {
+                    Ok(edited_args) => {
+                        *args = edited_args;
+                        return Confirmation::Approved;
+                    }
+                    Err(err) => println!("Invalid JSON ({err}), try again."),
+                }
+            }
+            other => println!("Unrecognized input `{other}`, try again."),
+        }
+    }
+}
+
+/// Keyed by call id so concurrent pending calls don't overwrite each other's
+/// snapshot file.
+fn tool_call_path(call_id: &str) -> String {
+    format!("tool_call-{call_id}.json")
+}
+
+fn announce_tool_call(call: &rig::message::ToolCall) {
+    print!(
+        "\n[Tool Call: {} with arguments {}]\n",
+        call.function.name, call.function.arguments
+    );
+
+    let mut tool_call_file = File::create(tool_call_path(&call.id)).unwrap();
+    serde_json::to_writer_pretty(&mut tool_call_file, call).unwrap();
+}
+
+fn read_checkpoints(log_path: &str) -> Vec<usize> {
+    let marker_path = format!("{log_path}.marker");
+    File::open(&marker_path)
+        .map(|file| {
+            std::io::BufReader::new(file)
+                .lines()
+                .filter_map(|line| line.ok()?.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Trailing run of tool calls in `history` with no matching `ToolResult` yet
+/// (a log can end mid-turn if the process was killed waiting on the
+/// file-based fallback), oldest first.
+fn pending_tool_calls(history: &[Message]) -> Vec<rig::message::ToolCall> {
+    let mut pending = Vec::new();
+
+    for message in history.iter().rev() {
+        let Message::Assistant { content, .. } = message else {
+            break;
+        };
+        let rig::message::AssistantContent::ToolCall(call) = content.first() else {
+            break;
+        };
+        pending.push(call.clone());
+    }
+
+    pending.reverse();
+    pending
+}
+
 struct HistoryManager<W: std::io::Write> {
     writer: W,
     history: Vec<Message>,
+    log_path: String,
 }
 
 impl<W: std::io::Write> HistoryManager<W> {
-    fn new(writer: W) -> Self {
+    fn new(writer: W, log_path: impl Into<String>) -> Self {
         Self {
             writer,
             history: vec![],
+            log_path: log_path.into(),
         }
     }
 
-    fn new_with_history(writer: W, history: Vec<Message>) -> Self {
-        Self { writer, history }
+    fn new_with_history(writer: W, history: Vec<Message>, log_path: impl Into<String>) -> Self {
+        Self {
+            writer,
+            history,
+            log_path: log_path.into(),
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.history.len()
+    }
+
+    fn write_checkpoint(&self) {
+        let marker_path = format!("{}.marker", self.log_path);
+        let mut marker_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&marker_path)
+            .unwrap();
+        marker_file
+            .write_all(format!("{}\n", self.position()).as_bytes())
+            .unwrap();
     }
 
     fn add_user_message(&mut self, text: String) {
@@ -47,9 +353,15 @@ impl<W: std::io::Write> HistoryManager<W> {
         self.history.push(message);
     }
 
-    fn handle_tool_call_result(&mut self, result: ToolResult) {
+    fn handle_tool_call_results(&mut self, results: Vec<ToolResult>) {
         let message = Message::User {
-            content: OneOrMany::one(rig::message::UserContent::ToolResult(result)),
+            content: OneOrMany::many(
+                results
+                    .into_iter()
+                    .map(rig::message::UserContent::ToolResult)
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap(),
         };
 
         self.writer
@@ -89,6 +401,38 @@ impl<W: std::io::Write> HistoryManager<W> {
     }
 }
 
+impl HistoryManager<File> {
+    fn rewind_to(&mut self, marker: usize) {
+        self.history.truncate(marker);
+
+        let mut file = File::create(&self.log_path).unwrap();
+        for message in &self.history {
+            file.write_all(format!("{}\n", serde_json::to_string(message).unwrap()).as_bytes())
+                .unwrap();
+        }
+        file.flush().unwrap();
+
+        self.writer = OpenOptions::new()
+            .write(true)
+            .append(true)
+            .open(&self.log_path)
+            .unwrap();
+
+        let marker_path = format!("{}.marker", self.log_path);
+        let remaining_checkpoints: Vec<usize> = read_checkpoints(&self.log_path)
+            .into_iter()
+            .filter(|checkpoint| *checkpoint <= marker)
+            .collect();
+
+        let mut marker_file = File::create(&marker_path).unwrap();
+        for checkpoint in remaining_checkpoints {
+            marker_file
+                .write_all(format!("{checkpoint}\n").as_bytes())
+                .unwrap();
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let client = Client::from_env();
@@ -108,15 +452,57 @@ async fn main() {
                 .open("conversation_log.jsonl")
                 .unwrap(),
             history,
+            "conversation_log.jsonl",
         )
     } else {
         let file = File::create("conversation_log.jsonl").unwrap();
-        HistoryManager::new(file)
+        HistoryManager::new(file, "conversation_log.jsonl")
     };
 
     let input = std::io::stdin();
     let mut lines = input.lines();
 
+    let checkpoints = read_checkpoints("conversation_log.jsonl");
+    if !checkpoints.is_empty() {
+        println!(
+            "Checkpoints available at message counts: {checkpoints:?} (log currently has {} messages).",
+            current_history.position()
+        );
+        print!("Resume at the latest turn (Enter), or enter a checkpoint to rewind to: ");
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+
+        if let Some(Ok(choice)) = lines.next()
+            && let Ok(marker) = choice.trim().parse::<usize>()
+        {
+            current_history.rewind_to(marker);
+        }
+    }
+
+    let mut tool_cache = if let Ok(file) = File::open("tool_cache.jsonl") {
+        let reader = std::io::BufReader::new(file);
+        let entries: HashMap<String, String> = reader
+            .lines()
+            .map(|line| {
+                let entry: ToolCacheEntry = serde_json::from_str(&line.unwrap()).unwrap();
+                (entry.key, entry.result)
+            })
+            .collect();
+
+        ToolCache::new_with_entries(
+            OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open("tool_cache.jsonl")
+                .unwrap(),
+            entries,
+        )
+    } else {
+        let file = File::create("tool_cache.jsonl").unwrap();
+        ToolCache::new(file)
+    };
+
+    let tool_registry = ToolRegistry::new();
+
     let tools = vec![
         ToolDefinition {
             name: "get_weather".to_string(),
@@ -134,28 +520,46 @@ async fn main() {
         }
     ];
 
-    let mut resume = if let Some(Message::Assistant { content, .. }) = current_history.history.last()
-    && let rig::message::AssistantContent::ToolCall(tool_call) = content.first()
-    {
-        println!(
-            "Resuming from last tool call: {} with arguments {}",
-            tool_call.function.name, tool_call.function.arguments
-        );
+    let pending = pending_tool_calls(&current_history.history);
+    let mut resume = !pending.is_empty();
 
-        let mut result = String::new();
-        File::open("tool_call.json").unwrap().read_to_string(&mut result).unwrap();
-        println!("Tool call result loaded from tool_call.json: {}", result);
+    if !pending.is_empty() {
+        let mut results = Vec::new();
 
-        current_history.handle_tool_call_result(ToolResult {
-            id: tool_call.id.clone(),
-            call_id: tool_call.call_id.clone(),
-            content: OneOrMany::one(ToolResultContent::Text(Text { text: result })),
-        });
+        for tool_call in &pending {
+            println!(
+                "Resuming from pending tool call: {} with arguments {}",
+                tool_call.function.name, tool_call.function.arguments
+            );
 
-        true
-    } else {
-        false
-    };
+            let cache_key = (!NO_CACHE_TOOLS.contains(&tool_call.function.name.as_str()))
+                .then(|| tool_cache_key(&tool_call.function.name, &tool_call.function.arguments));
+
+            let result = if let Some(cached) = cache_key.as_ref().and_then(|key| tool_cache.get(key)) {
+                println!("Tool call result loaded from cache: {}", cached);
+                cached.clone()
+            } else {
+                let path = tool_call_path(&tool_call.id);
+                let mut result = String::new();
+                File::open(&path).unwrap().read_to_string(&mut result).unwrap();
+                println!("Tool call result loaded from {path}: {result}");
+
+                if let Some(key) = cache_key {
+                    tool_cache.insert(key, tool_call.function.name.clone(), result.clone());
+                }
+
+                result
+            };
+
+            results.push(ToolResult {
+                id: tool_call.id.clone(),
+                call_id: tool_call.call_id.clone(),
+                content: OneOrMany::one(ToolResultContent::Text(Text { text: result })),
+            });
+        }
+
+        current_history.handle_tool_call_results(results);
+    }
 
     'outer: loop {
         if resume {
@@ -171,49 +575,141 @@ async fn main() {
             current_history.add_user_message(line);
         }
 
-        let gen_cfg = GenerationConfig::default();
-        let cfg = AdditionalParameters::default().with_config(gen_cfg);
-        let mut completion_result = gemini
-            .stream(CompletionRequest {
-                preamble: None,
-                chat_history: current_history.get_history(),
-                documents: vec![],
-                tools: tools.clone(),
-                temperature: None,
-                max_tokens: None,
-                tool_choice: None,
-                additional_params: Some(serde_json::to_value(cfg).unwrap()),
-            })
-            .await
-            .unwrap();
+        let mut steps_remaining = MAX_AGENT_STEPS;
+
+        'agent: loop {
+            let gen_cfg = GenerationConfig::default();
+            let cfg = AdditionalParameters::default().with_config(gen_cfg);
+            let mut completion_result = gemini
+                .stream(CompletionRequest {
+                    preamble: None,
+                    chat_history: current_history.get_history(),
+                    documents: vec![],
+                    tools: tools.clone(),
+                    temperature: None,
+                    max_tokens: None,
+                    tool_choice: None,
+                    additional_params: Some(serde_json::to_value(cfg).unwrap()),
+                })
+                .await
+                .unwrap();
+
+            let mut finished_calls: Vec<rig::message::ToolCall> = Vec::new();
 
-        while let Some(Ok(chunk)) = completion_result.next().await {
-            let was_call = match &chunk {
-                StreamedAssistantContent::Text(text) => {
-                    print!("{}", text.text);
-                    false
+            while let Some(Ok(chunk)) = completion_result.next().await {
+                match &chunk {
+                    StreamedAssistantContent::Text(text) => print!("{}", text.text),
+                    StreamedAssistantContent::ToolCall(call) => {
+                        announce_tool_call(call);
+                        finished_calls.push(call.clone());
+                    }
+                    StreamedAssistantContent::Final(_) => {}
+                    o => todo!("Unhandled chunk type: {:?}", o),
                 }
-                StreamedAssistantContent::ToolCall(call) => {
-                    print!(
-                        "\n[Tool Call: {} with arguments {}]\n",
-                        call.function.name,
-                        call.function.arguments
-                    );
 
-                    let mut tool_call_file = File::create("tool_call.json").unwrap();
-                    serde_json::to_writer_pretty(&mut tool_call_file, &call).unwrap();
+                current_history.handle_completion(chunk);
+            }
+
+            if finished_calls.is_empty() {
+                current_history.write_checkpoint();
+                break 'agent;
+            }
+
+            if steps_remaining == 0 {
+                println!("\n--- Max tool-call steps reached, stopping ---");
+                break 'outer;
+            }
+            steps_remaining -= 1;
+
+            let mut to_run = Vec::new();
+            let mut results = Vec::new();
 
-                    true
+            for call in &finished_calls {
+                match parse_and_validate_tool_args(call, &tools) {
+                    Ok(mut args) => {
+                        if requires_confirmation(&call.function.name)
+                            && matches!(
+                                confirm_tool_call(call, &mut args, &mut lines),
+                                Confirmation::Rejected
+                            )
+                        {
+                            results.push(ToolResult {
+                                id: call.id.clone(),
+                                call_id: call.call_id.clone(),
+                                content: OneOrMany::one(ToolResultContent::Text(Text {
+                                    text: format!(
+                                        "The user declined to run tool `{}`.",
+                                        call.function.name
+                                    ),
+                                })),
+                            });
+                            continue;
+                        }
+
+                        let cache_key = (!NO_CACHE_TOOLS.contains(&call.function.name.as_str()))
+                            .then(|| tool_cache_key(&call.function.name, &args));
+
+                        if let Some(cached) =
+                            cache_key.as_ref().and_then(|key| tool_cache.get(key))
+                        {
+                            results.push(ToolResult {
+                                id: call.id.clone(),
+                                call_id: call.call_id.clone(),
+                                content: OneOrMany::one(ToolResultContent::Text(Text {
+                                    text: cached.clone(),
+                                })),
+                            });
+                        } else {
+                            to_run.push((call, args, cache_key));
+                        }
+                    }
+                    Err(message) => results.push(ToolResult {
+                        id: call.id.clone(),
+                        call_id: call.call_id.clone(),
+                        content: OneOrMany::one(ToolResultContent::Text(Text { text: message })),
+                    }),
                 }
-                StreamedAssistantContent::Final(_) => {
-                    false
+            }
+
+            let outcomes = futures_util::future::join_all(
+                to_run
+                    .iter()
+                    .map(|(call, args, _)| tool_registry.dispatch(&call.function.name, args.clone())),
+            )
+            .await;
+
+            let mut unregistered = false;
+
+            for ((call, _, cache_key), outcome) in to_run.iter().zip(outcomes) {
+                match outcome {
+                    Some(Ok(result)) => {
+                        if let Some(key) = cache_key {
+                            tool_cache.insert(key.clone(), call.function.name.clone(), result.clone());
+                        }
+                        results.push(ToolResult {
+                            id: call.id.clone(),
+                            call_id: call.call_id.clone(),
+                            content: OneOrMany::one(ToolResultContent::Text(Text { text: result })),
+                        });
+                    }
+                    Some(Err(error)) => results.push(ToolResult {
+                        id: call.id.clone(),
+                        call_id: call.call_id.clone(),
+                        content: OneOrMany::one(ToolResultContent::Text(Text {
+                            text: format!("Tool `{}` failed: {error}", call.function.name),
+                        })),
+                    }),
+                    None => unregistered = true,
                 }
-                o => todo!("Unhandled chunk type: {:?}", o),
-            };
+            }
 
-            current_history.handle_completion(chunk);
+            // Persist in-hand results even if the batch also has an
+            // unregistered call below, so they aren't lost when we bail.
+            if !results.is_empty() {
+                current_history.handle_tool_call_results(results);
+            }
 
-            if was_call {
+            if unregistered {
                 println!("\n--- Conversation ended due to tool call ---");
                 break 'outer;
             }